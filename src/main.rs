@@ -1,9 +1,22 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Result;
+use byte_unit::{Byte, UnitType};
 use clap::Parser;
-use reqwest::Client;
-use tokio::{io::AsyncWriteExt, process::Command, time::Instant};
+use image::GenericImageView;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use rand::Rng;
+use reqwest::{Client, Response, StatusCode};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::mpsc,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -17,16 +30,195 @@ struct Args {
     verbose: bool,
     #[arg(long, short, default_value_t = 64)]
     concurrent_requests: usize,
-    url: String,
+    #[arg(long, default_value_t = 5)]
+    max_retries: u32,
+    /// Tile URL template, e.g. `https://tile.example/{z}/{x}/{y}.png`. Supports `{z}`, `{x}`,
+    /// `{y}`, `{tg}` (Zoomify tile group) and `{quadkey}` (Bing-style) placeholders. Falls back
+    /// to the Zoomify `TileGroup{n}` layout rooted at `url` when omitted.
+    #[arg(long)]
+    template: Option<String>,
+    #[arg(long)]
+    force: bool,
+    #[arg(required_unless_present = "template")]
+    url: Option<String>,
+}
+
+fn quadkey(x: i32, y: i32, zoom: i32) -> String {
+    (1..=zoom)
+        .rev()
+        .map(|i| {
+            let mask = 1 << (i - 1);
+            let mut digit = 0u8;
+            if x & mask != 0 {
+                digit += 1;
+            }
+            if y & mask != 0 {
+                digit += 2;
+            }
+            char::from_digit(digit as u32, 10).unwrap()
+        })
+        .collect()
+}
+
+fn uses_tile_groups(s: &Arc<State>) -> bool {
+    match &s.args.template {
+        Some(t) => t.contains("{tg}"),
+        None => true,
+    }
+}
+
+fn tile_url(s: &Arc<State>, x: i32, y: i32, zoom: i32, tile_group: i32) -> String {
+    match &s.args.template {
+        Some(t) => t
+            .replace("{z}", &zoom.to_string())
+            .replace("{x}", &x.to_string())
+            .replace("{y}", &y.to_string())
+            .replace("{tg}", &tile_group.to_string())
+            .replace("{quadkey}", &quadkey(x, y, zoom)),
+        None => format!(
+            "{}/TileGroup{tile_group}/{zoom}-{x}-{y}.jpg",
+            s.args.url.as_deref().unwrap_or_default()
+        ),
+    }
 }
 
 struct State {
     args: Args,
     client: Client,
-    width: tokio::sync::Mutex<i32>,
-    height: tokio::sync::Mutex<i32>,
     semaphor: tokio::sync::Semaphore,
-    count: tokio::sync::Mutex<i32>,
+    token: CancellationToken,
+}
+
+enum DownloadEvent {
+    Downloaded { bytes: u64 },
+    RowEnded { x: i32 },
+    ColumnEnded { y: i32 },
+}
+
+#[derive(Default)]
+struct GridStats {
+    width: i32,
+    height: i32,
+    count: i32,
+    bytes: u64,
+}
+
+fn human_bytes(n: u128) -> String {
+    Byte::from_u128(n)
+        .map(|b| b.get_appropriate_unit(UnitType::Decimal).to_string())
+        .unwrap_or_else(|| format!("{n} B"))
+}
+
+async fn run_coordinator(mut rx: mpsc::Receiver<DownloadEvent>, tile_bar: ProgressBar) -> GridStats {
+    let mut stats = GridStats::default();
+    let start = Instant::now();
+    while let Some(event) = rx.recv().await {
+        match event {
+            DownloadEvent::Downloaded { bytes } => {
+                stats.count += 1;
+                stats.bytes += bytes;
+
+                let elapsed = start.elapsed().as_secs_f64().max(0.001);
+                let rate = human_bytes((stats.bytes as f64 / elapsed) as u128);
+                let total = human_bytes(stats.bytes as u128);
+                tile_bar.set_message(format!("{total}, {rate}/s"));
+                tile_bar.inc(1);
+            }
+            DownloadEvent::RowEnded { x } => {
+                if x - 1 > stats.width {
+                    stats.width = x;
+                }
+                if stats.height > 0 {
+                    tile_bar.set_length((stats.width * stats.height) as u64);
+                }
+            }
+            DownloadEvent::ColumnEnded { y } => {
+                stats.height = y;
+                if stats.width > 0 {
+                    tile_bar.set_length((stats.width * stats.height) as u64);
+                }
+            }
+        }
+    }
+    tile_bar.finish_with_message("done");
+    stats
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+async fn backoff_sleep(attempt: u32) {
+    let base = Duration::from_millis(250);
+    let exp = base.saturating_mul(1 << attempt.min(8));
+    let capped = exp.min(Duration::from_secs(15));
+    let jitter = Duration::from_millis(rand::thread_rng().gen_range(0..250));
+    tokio::time::sleep(capped + jitter).await;
+}
+
+async fn request_with_retry(s: &Arc<State>, url: &str, desc: &str) -> Result<Response> {
+    let mut attempt = 0;
+    loop {
+        let semaphor = s.semaphor.acquire().await.unwrap();
+
+        if s.args.verbose {
+            println!("Requesting: {desc}")
+        }
+
+        let result = s.client.get(url).send().await;
+
+        drop(semaphor);
+
+        match result {
+            Ok(r) if !is_retryable_status(r.status()) => {
+                if s.args.verbose {
+                    println!("Requested: {desc}, status:{}", r.status())
+                }
+                return Ok(r);
+            }
+            Ok(r) => {
+                if attempt >= s.args.max_retries {
+                    anyhow::bail!("Exhausted retries for {desc}, last status: {}", r.status());
+                }
+                if s.args.verbose {
+                    println!(
+                        "Retrying {desc} after status {} (attempt {}/{})",
+                        r.status(),
+                        attempt + 1,
+                        s.args.max_retries
+                    )
+                }
+            }
+            Err(e) => {
+                if attempt >= s.args.max_retries {
+                    return Err(e.into());
+                }
+                if s.args.verbose {
+                    println!(
+                        "Retrying {desc} after transport error: {e} (attempt {}/{})",
+                        attempt + 1,
+                        s.args.max_retries
+                    )
+                }
+            }
+        }
+
+        backoff_sleep(attempt).await;
+        attempt += 1;
+    }
+}
+
+async fn is_valid_cached_tile(path: &Path) -> bool {
+    let Ok(mut file) = tokio::fs::File::open(path).await else {
+        return false;
+    };
+    let mut header = [0u8; 12];
+    if file.read_exact(&mut header).await.is_err() {
+        return false;
+    }
+    header.starts_with(&[0xFF, 0xD8])
+        || header.starts_with(b"\x89PNG")
+        || (header.starts_with(b"RIFF") && header[8..12] == *b"WEBP")
 }
 
 async fn download_file(
@@ -35,144 +227,215 @@ async fn download_file(
     y: i32,
     zoom: i32,
     mut tile_group: i32,
+    tx: Option<&mpsc::Sender<DownloadEvent>>,
 ) -> Result<i32> {
-    let mut r;
-    loop {
-        let url = format!("{}/TileGroup{tile_group}/{zoom}-{x}-{y}.jpg", s.args.url);
+    if s.token.is_cancelled() {
+        anyhow::bail!("Cancelled.");
+    }
 
-        let semaphor = s.semaphor.acquire().await.unwrap();
+    let path = s.args.dir.join(format!("{zoom}-{x}-{y}.jpg"));
 
+    if !s.args.force && is_valid_cached_tile(&path).await {
         if s.args.verbose {
-            println!("Requesting: tg{tile_group}/{zoom}-{x}-{y}",)
+            println!("Using cached tile {zoom}-{x}-{y}");
         }
+        if let Some(tx) = tx {
+            let bytes = tokio::fs::metadata(&path).await.map(|m| m.len()).unwrap_or(0);
+            let _ = tx.send(DownloadEvent::Downloaded { bytes }).await;
+        }
+        return Ok(tile_group);
+    }
 
-        r = s.client.get(&url).send().await.unwrap();
+    let track_tile_groups = uses_tile_groups(s);
+    let r;
+    loop {
+        let url = tile_url(s, x, y, zoom, tile_group);
+        let desc = format!("tg{tile_group}/{zoom}-{x}-{y}");
 
-        drop(semaphor);
+        let resp = request_with_retry(s, &url, &desc).await?;
 
-        if s.args.verbose {
-            println!(
-                "Requested: tg{tile_group}/{zoom}-{x}-{y}, status:{}",
-                r.status()
-            )
+        if resp.status().is_success() {
+            r = resp;
+            break;
         }
 
-        if r.status().is_success() {
-            break;
+        if !track_tile_groups {
+            anyhow::bail!("Tile not found at {zoom}-{x}-{y} (status {}).", resp.status());
         }
 
         tile_group += 1;
         if tile_group > s.args.max_tile_gropu {
-            anyhow::bail!("Max tile_group limit reached.\n{:?}", r);
+            anyhow::bail!("Max tile_group limit reached.\n{:?}", resp);
         }
     }
 
-    let mut file = tokio::fs::File::create(s.args.dir.join(format!("{zoom}-{x}-{y}.jpg"))).await?;
+    let mut file = tokio::fs::File::create(&path).await?;
 
-    file.write_all(&r.bytes().await?).await?;
+    let bytes = r.bytes().await?;
 
-    let mut count = s.count.lock().await;
+    file.write_all(&bytes).await?;
 
-    *count += 1;
-
-    if *count % 10 == 0 {
-        println!("Downloaded {count}/?",);
+    if let Some(tx) = tx {
+        let _ = tx
+            .send(DownloadEvent::Downloaded {
+                bytes: bytes.len() as u64,
+            })
+            .await;
     }
 
     Ok(tile_group)
 }
 
-async fn download_row(s: &Arc<State>, mut x: i32, y: i32, zoom: i32, mut tile_group: i32) {
-    while let Ok(tg) = download_file(s, x, y, zoom, tile_group).await {
+async fn download_row(
+    s: &Arc<State>,
+    mut x: i32,
+    y: i32,
+    zoom: i32,
+    mut tile_group: i32,
+    tx: mpsc::Sender<DownloadEvent>,
+) {
+    while let Ok(tg) = download_file(s, x, y, zoom, tile_group, Some(&tx)).await {
         x += 1;
         tile_group = tg;
     }
-    let mut m = s.width.lock().await;
-    if x - 1 > *m {
-        *m = x;
-    }
+    let _ = tx.send(DownloadEvent::RowEnded { x }).await;
 }
 
 async fn search_depth(s: &Arc<State>) {
     let start_time = Instant::now();
+
+    let multi = MultiProgress::new();
+
+    let probe_bar = multi.add(ProgressBar::new_spinner());
+    probe_bar.set_style(ProgressStyle::with_template("{spinner:.green} {msg}").unwrap());
+    probe_bar.enable_steady_tick(Duration::from_millis(120));
+    probe_bar.set_message("Searching for the highest zoom level...");
+
+    let tile_bar = multi.add(ProgressBar::new(0));
+    tile_bar.set_style(
+        ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos}/{len} tiles ({msg})",
+        )
+        .unwrap()
+        .progress_chars("#>-"),
+    );
+
+    let (tx, rx) = mpsc::channel(256);
+    let coordinator = tokio::spawn(run_coordinator(rx, tile_bar));
+
     let mut zoom = 0;
     let mut tile_group = 0;
-    while let Ok(tg) = download_file(s, 0, 0, zoom, tile_group).await {
+    while let Ok(tg) = download_file(s, 0, 0, zoom, tile_group, None).await {
         tile_group = tg;
         zoom += 1;
+        probe_bar.tick();
     }
 
-    *s.count.lock().await = 1;
-
     zoom -= 1;
 
-    println!("Found highest zoom: {zoom}");
+    probe_bar.finish_with_message(format!("Found highest zoom: {zoom}"));
+
+    // The (0,0) tile at the resolved zoom is already on disk from the probe
+    // above and is the first tile of the grid we're about to build.
+    let _ = tx.send(DownloadEvent::Downloaded { bytes: 0 }).await;
 
     let mut tk = Vec::new();
 
     let sc = s.clone();
+    let row_tx = tx.clone();
     tk.push(tokio::spawn(async move {
-        download_row(&sc, 1, 0, zoom, tile_group).await
+        download_row(&sc, 1, 0, zoom, tile_group, row_tx).await
     }));
 
     let mut y = 1;
-    while let Ok(tg) = download_file(s, 0, y, zoom, tile_group).await {
+    while let Ok(tg) = download_file(s, 0, y, zoom, tile_group, Some(&tx)).await {
         tile_group = tg;
         let sc = s.clone();
+        let row_tx = tx.clone();
         tk.push(tokio::spawn(async move {
-            download_row(&sc, 1, y, zoom, tile_group).await
+            download_row(&sc, 1, y, zoom, tile_group, row_tx).await
         }));
         y += 1;
     }
 
-    *s.height.lock().await = y;
+    let _ = tx.send(DownloadEvent::ColumnEnded { y }).await;
+    drop(tx);
 
     for j in tk {
         j.await.unwrap();
     }
 
-    let x = *s.width.lock().await;
-    let count = *s.count.lock().await;
+    let stats = coordinator.await.unwrap();
 
     println!(
-        "Downloaded {count}/{} ({x}x{y}) in {:.2}s",
-        x * y,
+        "Downloaded {}/{} ({}x{}) in {:.2}s",
+        stats.count,
+        stats.width * stats.height,
+        stats.width,
+        stats.height,
         start_time.elapsed().as_secs_f32()
     );
 
-    create_image(s, zoom).await;
+    create_image(s, zoom, stats.width, stats.height).await;
 }
 
-async fn create_image(s: &Arc<State>, zoom: i32) {
-    let start_time = Instant::now();
-    let mut c = Command::new("magick");
-    c.arg("montage");
-
-    let width = *s.width.lock().await;
-    let height = *s.height.lock().await;
-
-    let i = (0..height)
-        .map(|y| (0..width).map(move |x| (x, y)))
-        .flatten()
-        .map(move |(x, y)| format!("{}/{zoom}-{x}-{y}.jpg", s.args.dir.to_string_lossy()));
-
-    c.args(i);
+fn open_tile(path: &Path) -> Result<image::DynamicImage> {
+    Ok(image::ImageReader::open(path)?.with_guessed_format()?.decode()?)
+}
 
-    c.arg("-tile");
-    c.arg(format!("{}x{}", width, height));
-    c.arg("-geometry");
-    c.arg("256x256");
-    c.arg(&s.args.output);
+async fn create_image(s: &Arc<State>, zoom: i32, width: i32, height: i32) {
+    let start_time = Instant::now();
 
-    let o = c
-        .spawn()
-        .expect("Process couldn't be spawned.")
-        .wait_with_output()
-        .await
-        .expect("");
+    let top_left = s.args.dir.join(format!("{zoom}-0-0.jpg"));
+    let (tile_w, tile_h) = match open_tile(&top_left) {
+        Ok(img) => img.dimensions(),
+        Err(e) => {
+            eprintln!("Couldn't read {} to size the canvas: {e}", top_left.display());
+            return;
+        }
+    };
+
+    let mut canvas = image::RgbaImage::new(width as u32 * tile_w, height as u32 * tile_h);
+
+    for y in 0..height {
+        for x in 0..width {
+            let path = s.args.dir.join(format!("{zoom}-{x}-{y}.jpg"));
+            let tile = match open_tile(&path) {
+                Ok(img) => img,
+                Err(e) => {
+                    if s.args.verbose {
+                        println!("Skipping missing tile {}: {e}", path.display());
+                    }
+                    continue;
+                }
+            };
+            image::imageops::overlay(
+                &mut canvas,
+                &tile,
+                x as i64 * tile_w as i64,
+                y as i64 * tile_h as i64,
+            );
+        }
+    }
 
-    if !o.stderr.is_empty() {
-        println!("{}", String::from_utf8_lossy(&o.stderr));
+    let is_jpeg = s
+        .args
+        .output
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("jpg") || e.eq_ignore_ascii_case("jpeg"));
+
+    // The JPEG encoder doesn't support an alpha channel, so drop it before saving.
+    let save_result = if is_jpeg {
+        image::DynamicImage::ImageRgba8(canvas)
+            .into_rgb8()
+            .save(&s.args.output)
+    } else {
+        canvas.save(&s.args.output)
+    };
+
+    if let Err(e) = save_result {
+        eprintln!("Failed to save {}: {e}", s.args.output.display());
     }
 
     println!(
@@ -185,14 +448,27 @@ async fn create_image(s: &Arc<State>, zoom: i32) {
 async fn main() {
     let args = Args::parse();
     let client = Client::new();
+    let token = CancellationToken::new();
     let state = Arc::new(State {
         semaphor: tokio::sync::Semaphore::new(args.concurrent_requests),
         args,
         client,
-        width: tokio::sync::Mutex::new(0),
-        height: tokio::sync::Mutex::new(0),
-        count: tokio::sync::Mutex::new(0),
+        token: token.clone(),
     });
 
-    search_depth(&state).await;
+    let mut handle = tokio::spawn({
+        let state = state.clone();
+        async move { search_depth(&state).await }
+    });
+
+    tokio::select! {
+        res = &mut handle => {
+            res.unwrap();
+        }
+        _ = tokio::signal::ctrl_c() => {
+            println!("\nReceived Ctrl-C, stopping new requests and stitching what's been downloaded so far...");
+            token.cancel();
+            handle.await.unwrap();
+        }
+    }
 }